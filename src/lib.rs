@@ -62,24 +62,106 @@
 //!
 //! ## Notes on concurrency
 //!
-//! Coverage is tracked via shared mutable state, so the following
-//! caveat applies:
+//! A live `covers!("x")` claims mark `"x"` for its whole lifetime. Two
+//! things follow from that:
 //!
-//!   * A `covers!` from one test might be covered by thread of *another* test.
-//!     As a result, a test might pass when it should have failed.
+//!   * A second, concurrent `covers!("x")` on any *other* thread blocks until
+//!     the first one drops, so checks for the same mark on different threads
+//!     never overlap. A nested `covers!("x")` on the same thread (or on a
+//!     thread that [`adopt()`]ed the claim, see below) doesn't block; it
+//!     shares the existing claim.
+//!   * A `covered_by!("x")` hit from any *other* thread blocks until the
+//!     claim is released, so a hit can never be credited to a guard it
+//!     didn't actually happen under — the guard's pass/fail verdict is
+//!     decided from the hits of its own thread, and of threads that adopted
+//!     its claim, alone.
 //!
-//! The error in the opposite direction never happens: if your code covers everything
-//! with a single thread, it will do it with several threads as well.
+//! Checks for *different* mark names run fully in parallel; only same-name
+//! traffic is ever serialized. A claim is a name in a shared set rather
+//! than a held lock: the set's mutex is only taken for the instant it takes
+//! to test-and-set a claim or bump a counter, and no lock is held across a
+//! guard's whole scope.
+//!
+//! The one thing the library can't infer on its own is thread *lineage*:
+//! code under test routinely spawns a worker thread and joins it from
+//! inside a `covers!` scope, and that worker's `covered_by!` is meant to
+//! count towards the enclosing guard, not queue behind it forever. Since a
+//! spawned thread has no automatic link back to the thread that spawned it,
+//! tell the library about it explicitly with [`owned_marks()`] and
+//! [`adopt()`]:
+//!
+//! ```
+//! # #[macro_use] extern crate uncover;
+//! # define_uncover_macros!(enable_if(true));
+//! fn work() { covered_by!("worker_hit"); }
+//!
+//! # fn main() {
+//! covers!("worker_hit");
+//! let owned = uncover::owned_marks();
+//! std::thread::spawn(move || {
+//!     let _adopted = uncover::adopt(owned);
+//!     work();
+//! })
+//! .join()
+//! .unwrap();
+//! # }
+//! ```
+//!
+//! ## Whole-suite dead-mark detection
+//!
+//! `covers!` catches a mark that a *specific* test fails to exercise. The
+//! inverse question — is there a `covered_by!` site that *no* test exercises
+//! — is answered by [`report()`], which lists every instrumented mark and
+//! its hit count, and [`assert_all_covered()`], which panics listing every
+//! mark with a zero count. Call `assert_all_covered()` from a final
+//! `#[test]` (or test harness teardown) to guard against instrumentation
+//! going stale.
 
 #[macro_use]
 extern crate lazy_static;
 
+// Re-exported so `covered_by!`'s expansion, which runs in the caller's
+// crate, can reach `inventory::submit!` through `$crate::inventory`.
+#[doc(hidden)]
+pub extern crate inventory;
+
 use std::{
-    sync::Mutex,
-    collections::HashMap,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Condvar, Mutex},
 };
 
 
+/// Where a `covers!` or `covered_by!` was written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub file: &'static str,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// The type of the hidden marker item the identifier form of `covered_by!`
+/// declares. `covers!` et al. type-check `$name` against this (rather than
+/// just resolving the name) so that some unrelated, same-named local
+/// variable or import can't silently stand in for a real mark.
+#[doc(hidden)]
+pub struct __Mark;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __here {
+    () => {
+        $crate::Location { file: file!(), line: line!(), column: column!() }
+    };
+}
+
 /// Define `covered_by!` and `covers!` macros.
 ///
 /// Use `covered_by!("unique_name")` in the code and
@@ -107,6 +189,32 @@ use std::{
 ///     enable_if(option_env!("CI") == Some("1"))
 /// );
 /// ```
+///
+/// Besides a string literal, both macros also accept a bare identifier:
+/// `covered_by!(short_date)` / `covers!(short_date)`. `covered_by!` then
+/// declares a hidden, specially-typed marker item named after the
+/// identifier, and `covers!` type-checks `$name` against that same type, so
+/// a `covers!` for a mark that is never instrumented fails to *compile*
+/// rather than merely failing at runtime — and some unrelated in-scope
+/// identifier of the wrong type can't silently satisfy the check either.
+///
+/// Because the marker is a plain item, it follows normal Rust item scoping:
+/// it's local to the exact block `covered_by!` is called from, so `covers!`
+/// can only see it when both calls share that same block. This makes the
+/// identifier form a good fit for self-contained checks (a mark hit and
+/// checked within the same function), but the usual case of a hit site deep
+/// in library code being checked from a separate test still needs the
+/// string-literal form.
+///
+/// Two more macros pin down *how many* times a mark fires, rather than just
+/// "at least once": `covers_count!("unique_name", n)` panics at scope exit
+/// unless the mark was hit exactly `n` times during the scope, and
+/// `covers_not!("unique_name")` panics if it was hit at all. Both accept the
+/// identifier form too.
+///
+/// A failing `covers!`/`covers_count!`/`covers_not!` reports the source
+/// location it was written at, plus any *other* marks that were hit during
+/// its scope — usually the actual, misnamed or moved, hit site.
 #[macro_export]
 macro_rules! define_uncover_macros {
     (enable_if($cond:expr)) => {
@@ -117,19 +225,86 @@ macro_rules! define_uncover_macros {
 
         #[macro_export]
         macro_rules! covers {
+            ($name:ident) => {
+                let mut _guard = None;
+                if $cond {
+                    // Type-checking `$name` against `__Mark` here is what makes
+                    // checking a never-instrumented mark a compile error, and rules
+                    // out some unrelated in-scope identifier of the wrong type
+                    // silently satisfying the check.
+                    let _: $crate::__Mark = $name;
+                    _guard = Some($crate::__CoversGuard::new(
+                        stringify!($name),
+                        $crate::__here!(),
+                    ))
+                }
+            };
+            ($pos:expr) => {
+                let mut _guard = None;
+                if $cond {
+                    _guard = Some($crate::__CoversGuard::new($pos, $crate::__here!()))
+                }
+            };
+        }
+
+        #[macro_export]
+        macro_rules! covers_count {
+            ($name:ident, $n:expr) => {
+                let mut _guard = None;
+                if $cond {
+                    let _: $crate::__Mark = $name;
+                    _guard = Some($crate::__CoversGuard::with_expected(
+                        stringify!($name),
+                        $n,
+                        $crate::__here!(),
+                    ))
+                }
+            };
+            ($pos:expr, $n:expr) => {
+                let mut _guard = None;
+                if $cond {
+                    _guard =
+                        Some($crate::__CoversGuard::with_expected($pos, $n, $crate::__here!()))
+                }
+            };
+        }
+
+        #[macro_export]
+        macro_rules! covers_not {
+            ($name:ident) => {
+                let mut _guard = None;
+                if $cond {
+                    let _: $crate::__Mark = $name;
+                    _guard = Some($crate::__CoversGuard::with_expected(
+                        stringify!($name),
+                        0,
+                        $crate::__here!(),
+                    ))
+                }
+            };
             ($pos:expr) => {
                 let mut _guard = None;
                 if $cond {
-                    _guard = Some($crate::__CoversGuard::new($pos))
+                    _guard = Some($crate::__CoversGuard::with_expected($pos, 0, $crate::__here!()))
                 }
             };
         }
 
         #[macro_export]
         macro_rules! covered_by {
+            ($name:ident) => {
+                #[allow(non_upper_case_globals)]
+                #[doc(hidden)]
+                pub const $name: $crate::__Mark = $crate::__Mark;
+                $crate::inventory::submit! { $crate::__MarkSite { name: stringify!($name) } }
+                if $cond {
+                    $crate::__covers_record_coverage(stringify!($name), $crate::__here!());
+                }
+            };
             ($pos:expr) => {
+                $crate::inventory::submit! { $crate::__MarkSite { name: $pos } }
                 if $cond {
-                    $crate::__covers_record_coverage($pos);
+                    $crate::__covers_record_coverage($pos, $crate::__here!());
                 }
             };
         }
@@ -138,17 +313,183 @@ macro_rules! define_uncover_macros {
 
 
 lazy_static! {
-    static ref STATE: Mutex<HashMap<&'static str, u64>> = Default::default();
+    // The location stored alongside each count is where that mark was most
+    // recently hit, i.e. the `covered_by!` site.
+    static ref STATE: Mutex<HashMap<&'static str, (u64, Location)>> = Default::default();
+    // Marks with a currently-live `covers!` guard somewhere. A new guard
+    // for a mark already in this set waits on `ACTIVE_GUARDS_COND` until
+    // the existing one is dropped, which is what keeps two checks for the
+    // same mark from ever overlapping. A `covered_by!` for a mark in this
+    // set, from a thread that doesn't own the claim (see `OWNED_CLAIMS`),
+    // waits on the same condvar, which keeps a foreign hit from ever being
+    // credited to a guard it didn't happen under. Note this is a plain
+    // `Mutex<()>`-free design on purpose: nothing here is ever held across
+    // a guard's whole scope, only for the instant it takes to test-and-set
+    // or clear a name, so a panic inside a guard's `Drop` can never poison
+    // it.
+    static ref ACTIVE_GUARDS: Mutex<HashSet<&'static str>> = Default::default();
+    static ref ACTIVE_GUARDS_COND: Condvar = Condvar::new();
+}
+
+thread_local! {
+    // Marks this thread currently owns: either because its own live
+    // `covers!` holds the claim in `ACTIVE_GUARDS`, or because it called
+    // `adopt()` with marks owned by the thread that spawned it. Lets a
+    // `covers!`/`covered_by!` for an owned mark recognize it doesn't need
+    // to wait, instead of queuing behind its own (or its parent's) claim.
+    static OWNED_CLAIMS: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+}
+
+fn lock<'a, T>(mutex: &'a Mutex<T>) -> ::std::sync::MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn claim_guard(pos: &'static str) {
+    let mut active = lock(&ACTIVE_GUARDS);
+    while active.contains(pos) {
+        active = ACTIVE_GUARDS_COND.wait(active).unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+    active.insert(pos);
+}
+
+fn release_guard(pos: &'static str) {
+    lock(&ACTIVE_GUARDS).remove(pos);
+    ACTIVE_GUARDS_COND.notify_all();
+}
+
+// Blocks while `pos` is claimed by a `covers!` guard this thread doesn't
+// own, so a `covered_by!` hit can never land inside, and be credited to, a
+// guard it didn't actually run under. Returns immediately once the claim is
+// unowned or owned by this thread (directly, or via `adopt()`). In the
+// unowned case it returns the still-held `ACTIVE_GUARDS` lock, so the caller
+// can record the hit before any new guard gets to claim `pos`.
+fn wait_for_foreign_claim_to_clear(
+    pos: &'static str,
+) -> Option<::std::sync::MutexGuard<'static, HashSet<&'static str>>> {
+    if OWNED_CLAIMS.with(|owned| owned.borrow().contains(pos)) {
+        return None;
+    }
+    let mut active = lock(&ACTIVE_GUARDS);
+    while active.contains(pos) {
+        active = ACTIVE_GUARDS_COND.wait(active).unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+    Some(active)
 }
 
 #[doc(hidden)]
-pub fn __covers_record_coverage(pos: &'static str) {
-    *STATE.lock().unwrap().entry(pos).or_insert(0) += 1;
+pub fn __covers_record_coverage(pos: &'static str, site: Location) {
+    let _active = wait_for_foreign_claim_to_clear(pos);
+    let mut state = lock(&STATE);
+    let entry = state.entry(pos).or_insert((0, site));
+    entry.0 += 1;
+    entry.1 = site;
+}
+
+/// The marks the current thread owns: named in a live `covers!` on this
+/// thread, or previously passed to [`adopt()`] on this thread. Pass this to
+/// [`adopt()`] on a worker thread spawned from inside one of those
+/// `covers!` scopes, so the worker's `covered_by!` doesn't queue behind the
+/// very guard that's about to join it.
+pub fn owned_marks() -> Vec<&'static str> {
+    OWNED_CLAIMS.with(|owned| owned.borrow().iter().copied().collect())
+}
+
+/// Extends the current thread's ownership to `marks` (see [`owned_marks()`])
+/// for as long as the returned [`AdoptedClaims`] stays alive.
+pub fn adopt(marks: Vec<&'static str>) -> AdoptedClaims {
+    OWNED_CLAIMS.with(|owned| owned.borrow_mut().extend(marks.iter().copied()));
+    AdoptedClaims { marks }
+}
+
+/// Releases, on drop, the marks an [`adopt()`] call added to the current
+/// thread's ownership.
+pub struct AdoptedClaims {
+    marks: Vec<&'static str>,
+}
+
+impl Drop for AdoptedClaims {
+    fn drop(&mut self) {
+        OWNED_CLAIMS.with(|owned| {
+            let mut owned = owned.borrow_mut();
+            for mark in &self.marks {
+                owned.remove(mark);
+            }
+        });
+    }
 }
 
 #[doc(hidden)]
 pub fn __covers_get_coverage(pos: &'static str) -> u64 {
-    *STATE.lock().unwrap().get(pos).unwrap_or(&0)
+    lock(&STATE).get(pos).map_or(0, |&(cnt, _)| cnt)
+}
+
+/// A snapshot of every mark's hit count, for diffing against later.
+fn __covers_snapshot() -> HashMap<&'static str, u64> {
+    lock(&STATE).iter().map(|(&name, &(cnt, _))| (name, cnt)).collect()
+}
+
+/// Marks (other than `except`) whose count grew since `snapshot`, formatted
+/// as `"name" (file:line:col, +delta)` and sorted by name — candidates for
+/// what a failing `covers!` was probably meant to name.
+fn __covers_near_misses(snapshot: &HashMap<&'static str, u64>, except: &'static str) -> Vec<String> {
+    let state = lock(&STATE);
+    let mut near_misses: Vec<String> = state
+        .iter()
+        .filter(|&(&name, _)| name != except)
+        .filter_map(|(&name, &(cnt, site))| {
+            let before = snapshot.get(name).copied().unwrap_or(0);
+            if cnt > before {
+                Some(format!("{:?} ({}, +{})", name, site, cnt - before))
+            } else {
+                None
+            }
+        })
+        .collect();
+    near_misses.sort();
+    near_misses
+}
+
+/// A `covered_by!` call site, registered automatically for every
+/// instrumented mark regardless of whether it has ever been hit.
+#[doc(hidden)]
+pub struct __MarkSite {
+    pub name: &'static str,
+}
+
+inventory::collect!(__MarkSite);
+
+/// All marks instrumented with `covered_by!` across the whole binary,
+/// together with how many times each has been hit so far.
+///
+/// The list is sorted by name and deduplicated, since the same mark can be
+/// registered by more than one `covered_by!` call site.
+pub fn report() -> Vec<(&'static str, u64)> {
+    let mut names: Vec<&'static str> =
+        inventory::iter::<__MarkSite>().map(|site| site.name).collect();
+    names.sort_unstable();
+    names.dedup();
+    names.into_iter().map(|name| (name, __covers_get_coverage(name))).collect()
+}
+
+/// Panics, listing every instrumented mark that has never been hit.
+///
+/// Call this from a final `#[test]`, or from test harness teardown, to
+/// guarantee that no `covered_by!` site has gone stale.
+pub fn assert_all_covered() {
+    let dead: Vec<&'static str> =
+        report().into_iter().filter(|&(_, cnt)| cnt == 0).map(|(name, _)| name).collect();
+    if !dead.is_empty() {
+        panic!("marks never covered: {:?}", dead);
+    }
+}
+
+/// A plain-text dump of [`report`], one `name: count` line per mark.
+pub fn dump() -> String {
+    report()
+        .into_iter()
+        .map(|(name, cnt)| format!("{}: {}", name, cnt))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 
@@ -156,23 +497,81 @@ pub fn __covers_get_coverage(pos: &'static str) -> u64 {
 pub struct __CoversGuard {
     pos: &'static str,
     cnt: u64,
+    // Where this `covers!`/`covers_count!`/`covers_not!` was written.
+    site: Location,
+    // `None` means "at least once" (the original `covers!` behavior),
+    // `Some(n)` means "exactly `n` times" (`covers_count!` / `covers_not!`).
+    expected: Option<u64>,
+    // Every mark's count as of guard creation, so a failure can report which
+    // *other* marks were hit during this guard's scope.
+    snapshot: HashMap<&'static str, u64>,
+    // Whether this guard is the one that claimed `pos` in `ACTIVE_GUARDS`
+    // (false for a nested `covers!` of the same mark on the same thread,
+    // which just piggybacks on the outer guard's claim), and so must
+    // release it on drop.
+    owns_claim: bool,
 }
 
 impl __CoversGuard {
     #[doc(hidden)]
-    pub fn new(pos: &'static str) -> __CoversGuard {
+    pub fn new(pos: &'static str, site: Location) -> __CoversGuard {
+        __CoversGuard::with_expected(pos, None, site)
+    }
+
+    #[doc(hidden)]
+    pub fn with_expected<E: Into<Option<u64>>>(
+        pos: &'static str,
+        expected: E,
+        site: Location,
+    ) -> __CoversGuard {
+        let expected = expected.into();
+        let owns_claim = OWNED_CLAIMS.with(|owned| owned.borrow_mut().insert(pos));
+        if owns_claim {
+            claim_guard(pos);
+        }
         let cnt = __covers_get_coverage(pos);
-        __CoversGuard { pos, cnt }
+        let snapshot = __covers_snapshot();
+        __CoversGuard { pos, cnt, site, expected, snapshot, owns_claim }
     }
 }
 
 impl Drop for __CoversGuard {
     fn drop(&mut self) {
-        if ::std::thread::panicking() {
-            return;
+        // Decide pass/fail — and gather near-misses — while this guard
+        // still holds its claim, i.e. before releasing it below wakes up
+        // any foreign `covered_by!` queued behind it. Deciding afterwards
+        // would let such a hit land, and get credited to this guard, in
+        // the gap between releasing and reading the final count.
+        let message = if ::std::thread::panicking() {
+            None
+        } else {
+            let delta = __covers_get_coverage(self.pos) - self.cnt;
+            match self.expected {
+                None if delta == 0 => Some(format!("not covered: {:?} ({})", self.pos, self.site)),
+                None => None,
+                Some(n) if delta != n => Some(format!(
+                    "{:?} ({}) covered {} time(s), expected {}",
+                    self.pos, self.site, delta, n,
+                )),
+                Some(_) => None,
+            }
+        };
+        let near_misses = message.is_some().then(|| __covers_near_misses(&self.snapshot, self.pos));
+
+        if self.owns_claim {
+            OWNED_CLAIMS.with(|owned| { owned.borrow_mut().remove(self.pos); });
+            release_guard(self.pos);
         }
-        if !(self.cnt < __covers_get_coverage(self.pos)) {
-            panic!("not covered: {:?}", self.pos);
+
+        let mut message = match message {
+            Some(message) => message,
+            None => return,
+        };
+        let near_misses = near_misses.unwrap();
+        if !near_misses.is_empty() {
+            message.push_str("\nmarks hit during this scope instead: ");
+            message.push_str(&near_misses.join(", "));
         }
+        panic!("{}", message);
     }
 }
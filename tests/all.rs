@@ -41,3 +41,156 @@ fn no_multithreaded_false_positives() {
         }
     })
 }
+
+// A failing `covers!` panics inside its own `Drop`, while the guard that
+// serializes checks for that mark is still live. Regression test for that
+// panic poisoning the mark for good: a later, unrelated, passing `covers!`
+// for the same mark must keep working no matter how the two tests happen
+// to be scheduled relative to each other.
+fn again() {
+    covered_by!("again");
+}
+
+#[test]
+#[should_panic]
+fn mark_reused_after_failure_fails_as_expected() {
+    covers!("again");
+    baz();
+}
+
+#[test]
+fn mark_reused_after_failure_still_works() {
+    covers!("again");
+    again();
+}
+
+// Regression test: code under test routinely spawns a worker thread and
+// joins it from inside a `covers!` scope. The worker's `covered_by!` must
+// not deadlock against the very guard it's running under, as long as it
+// `adopt()`s the marks the spawning thread owns.
+#[test]
+fn covers_scope_spanning_a_join_does_not_deadlock() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        covers!("foo");
+        let owned = uncover::owned_marks();
+        std::thread::spawn(move || {
+            let _adopted = uncover::adopt(owned);
+            foo();
+        })
+        .join()
+        .unwrap();
+        tx.send(()).unwrap();
+    });
+    rx.recv_timeout(std::time::Duration::from_secs(5))
+        .expect("a covers! scope spanning a spawn+join deadlocked");
+}
+
+// Regression test: an unrelated, unguarded background thread hitting the
+// same mark name while a `covers!` guard for it is alive must not satisfy
+// that guard — it hasn't `adopt()`-ed the guard's claim, so its hits queue
+// behind it instead of being credited to it.
+#[test]
+fn covers_failure_not_masked_by_unrelated_background_hit() {
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (stop_tx, stop_rx) = std::sync::mpsc::channel();
+    let background = std::thread::spawn(move || {
+        started_tx.send(()).unwrap();
+        while stop_rx.recv_timeout(std::time::Duration::from_micros(10)).is_err() {
+            covered_by!("race_mark");
+        }
+    });
+    started_rx.recv().unwrap();
+
+    let result = std::panic::catch_unwind(|| {
+        covers!("race_mark");
+        // Deliberately never hits "race_mark" itself: the background
+        // thread's concurrent, unadopted hits must not satisfy this guard.
+        // The sleep widens the race window so a false positive isn't just
+        // a matter of being unlucky with scheduling.
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    });
+
+    stop_tx.send(()).unwrap();
+    background.join().unwrap();
+    assert!(result.is_err(), "an unrelated concurrent hit satisfied this guard");
+}
+
+// The identifier form is only usable when `covers!` and `covered_by!` share
+// the exact same block (see `define_uncover_macros!`'s doc comment), which
+// this test exercises directly.
+#[test]
+fn identifier_form_works_within_a_single_block() {
+    covers!(same_block_mark);
+    covered_by!(same_block_mark);
+}
+
+fn three_times() {
+    covered_by!("three_times");
+    covered_by!("three_times");
+    covered_by!("three_times");
+}
+
+#[test]
+fn covers_count_passes_on_exact_match() {
+    covers_count!("three_times", 3);
+    three_times();
+}
+
+#[test]
+#[should_panic]
+fn covers_count_fails_on_mismatch() {
+    covers_count!("three_times", 2);
+    three_times();
+}
+
+#[test]
+fn covers_not_passes_when_never_hit() {
+    covers_not!("never_hit_mark");
+}
+
+#[test]
+#[should_panic]
+fn covers_not_fails_when_hit() {
+    covers_not!("hit_once_mark");
+    covered_by!("hit_once_mark");
+}
+
+// `covered_by!` registers its mark via `inventory` whether or not this
+// function is ever called, so just declaring it is enough to give
+// `report()`/`assert_all_covered()` a permanently dead mark to find.
+#[allow(dead_code)]
+fn never_called() {
+    covered_by!("chunk4_dead_mark");
+}
+
+#[test]
+fn report_and_dump_see_every_instrumented_mark() {
+    covered_by!("chunk4_live_mark");
+    let report = uncover::report();
+    assert!(report.iter().any(|&(name, cnt)| name == "chunk4_live_mark" && cnt >= 1));
+    assert!(report.iter().any(|&(name, cnt)| name == "chunk4_dead_mark" && cnt == 0));
+    assert!(uncover::dump().contains("chunk4_live_mark: "));
+}
+
+#[test]
+#[should_panic]
+fn assert_all_covered_fails_while_a_mark_is_dead() {
+    uncover::assert_all_covered();
+}
+
+fn chunk5_helper() {
+    covered_by!("chunk5_actual");
+}
+
+#[test]
+fn failure_message_reports_location_and_near_miss() {
+    let result = std::panic::catch_unwind(|| {
+        covers!("chunk5_expected");
+        chunk5_helper();
+    });
+    let message = *result.unwrap_err().downcast::<String>().unwrap();
+    assert!(message.contains("chunk5_expected"));
+    assert!(message.contains(file!()));
+    assert!(message.contains("chunk5_actual"));
+}